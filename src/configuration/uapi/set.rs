@@ -0,0 +1,279 @@
+use std::io;
+use std::io::BufRead;
+use std::net::{IpAddr, SocketAddr};
+
+use x25519_dalek::{PublicKey, StaticSecret};
+
+use super::Configuration;
+
+// parse a "<ip>/<cidr>" allowed-ip entry, as emitted by `get::serialize`
+fn parse_allowed_ip(value: &str) -> Option<(IpAddr, u32)> {
+    let mut parts = value.splitn(2, '/');
+    let ip = parts.next()?.parse().ok()?;
+    let cidr = parts.next()?.parse().ok()?;
+    Some((ip, cidr))
+}
+
+// decode a 32-byte hex-encoded key (public, private or preshared)
+fn decode_key(value: &str) -> Option<[u8; 32]> {
+    let bytes = hex::decode(value).ok()?;
+    if bytes.len() != 32 {
+        return None;
+    }
+    let mut key = [0u8; 32];
+    key.copy_from_slice(&bytes);
+    Some(key)
+}
+
+fn reply<W: io::Write>(writer: &mut W, errno: i32) -> io::Result<()> {
+    writer.write_all(b"errno=")?;
+    writer.write_all(errno.to_string().as_ref())?;
+    writer.write_all(b"\n")
+}
+
+// `Configuration::set_listen_port`/`set_fwmark`/`set_persistent_keepalive_interval`
+// return `io::Result<()>`; `deserialize` turns such an error into an errno reply.
+//
+// Prefer the OS error code the setter attached, if any; a setter built from
+// `io::Error::new`/`From<ErrorKind>` carries no OS code, so fall back to mapping its
+// `ErrorKind` to the nearest errno rather than flattening every such rejection to EIO,
+// which would make any more specific setter failure indistinguishable from one.
+fn io_errno(err: &io::Error) -> i32 {
+    err.raw_os_error().unwrap_or_else(|| match err.kind() {
+        io::ErrorKind::NotFound => libc::ENOENT,
+        io::ErrorKind::PermissionDenied => libc::EACCES,
+        io::ErrorKind::AlreadyExists => libc::EEXIST,
+        io::ErrorKind::InvalidInput | io::ErrorKind::InvalidData => libc::EINVAL,
+        io::ErrorKind::TimedOut => libc::ETIMEDOUT,
+        io::ErrorKind::AddrInUse => libc::EADDRINUSE,
+        io::ErrorKind::AddrNotAvailable => libc::EADDRNOTAVAIL,
+        _ => libc::EIO,
+    })
+}
+
+// a setter failed after the value parsed fine; report its errno to the client
+// instead of bubbling the error out of `deserialize`, which would leave `wg`
+// waiting on a reply that never comes
+fn reply_err<W: io::Write>(writer: &mut W, err: io::Error) -> io::Result<()> {
+    reply(writer, io_errno(&err))
+}
+
+/// Parse and apply a UAPI "set=1" operation to the given configuration.
+///
+/// The caller is responsible for stripping the request down to the body following
+/// the initial `set=1` line (and the terminating blank line, if any); this function
+/// consumes key/value pairs until EOF, applying interface-level keys directly to
+/// `config` and peer-level keys to whichever `public_key` was last seen.
+///
+/// On success a trailing `errno=0` is written to `writer`, matching the reply format
+/// expected by `wg`/`wg-quick`. Malformed input (bad hex, unparsable values) or a
+/// setter rejecting an otherwise well-formed value both abort the operation and
+/// report a non-zero errno, as required by the protocol; only a failure to write to
+/// `writer` itself is propagated as an `io::Result` error.
+pub fn deserialize<C: Configuration, R: io::BufRead, W: io::Write>(
+    reader: &mut R,
+    writer: &mut W,
+    config: &C,
+) -> io::Result<()> {
+    let mut current_peer: Option<PublicKey> = None;
+
+    for line in reader.lines() {
+        let line = line?;
+        if line.is_empty() {
+            continue;
+        }
+
+        let mut parts = line.splitn(2, '=');
+        let key = match parts.next() {
+            Some(key) => key,
+            None => continue,
+        };
+        let value = match parts.next() {
+            Some(value) => value,
+            None => continue,
+        };
+
+        log::trace!("UAPI: set : {} = {}", key, value);
+
+        match key {
+            // interface-level operations
+
+            "private_key" => {
+                if value.is_empty() {
+                    config.set_private_key(None);
+                } else {
+                    match decode_key(value) {
+                        Some(sk) => config.set_private_key(Some(StaticSecret::from(sk))),
+                        None => return reply(writer, libc::EINVAL),
+                    }
+                }
+            }
+
+            "listen_port" => match value.parse() {
+                Ok(port) => {
+                    if let Err(e) = config.set_listen_port(port) {
+                        return reply_err(writer, e);
+                    }
+                }
+                Err(_) => return reply(writer, libc::EINVAL),
+            },
+
+            // unlike `private_key`, `fwmark=0` needs no sentinel-based "clear": 0 is
+            // already the kernel's "no mark" value, so passing it straight through
+            // to `set_fwmark` like any other value *is* the clear operation
+            "fwmark" => match value.parse() {
+                Ok(fwmark) => {
+                    if let Err(e) = config.set_fwmark(fwmark) {
+                        return reply_err(writer, e);
+                    }
+                }
+                Err(_) => return reply(writer, libc::EINVAL),
+            },
+
+            "replace_peers" if value == "true" => config.replace_peers(),
+
+            // peer-level operations
+
+            "public_key" => match decode_key(value) {
+                Some(pk) => {
+                    let pk = PublicKey::from(pk);
+                    config.add_peer(&pk);
+                    current_peer = Some(pk);
+                }
+                None => return reply(writer, libc::EINVAL),
+            },
+
+            "remove" if value == "true" => {
+                let pk = match &current_peer {
+                    Some(pk) => pk,
+                    None => return reply(writer, libc::EINVAL),
+                };
+                config.remove_peer(pk);
+                current_peer = None;
+            }
+
+            "preshared_key" => {
+                let pk = match &current_peer {
+                    Some(pk) => pk,
+                    None => return reply(writer, libc::EINVAL),
+                };
+                match decode_key(value) {
+                    Some(psk) => config.set_preshared_key(pk, psk),
+                    None => return reply(writer, libc::EINVAL),
+                }
+            }
+
+            "endpoint" => {
+                let pk = match &current_peer {
+                    Some(pk) => pk,
+                    None => return reply(writer, libc::EINVAL),
+                };
+                match value.parse::<SocketAddr>() {
+                    Ok(addr) => config.set_endpoint(pk, addr),
+                    Err(_) => return reply(writer, libc::EINVAL),
+                }
+            }
+
+            "persistent_keepalive_interval" => {
+                let pk = match &current_peer {
+                    Some(pk) => pk,
+                    None => return reply(writer, libc::EINVAL),
+                };
+                match value.parse() {
+                    Ok(secs) => {
+                        if let Err(e) = config.set_persistent_keepalive_interval(pk, secs) {
+                            return reply_err(writer, e);
+                        }
+                    }
+                    Err(_) => return reply(writer, libc::EINVAL),
+                }
+            }
+
+            "replace_allowed_ips" if value == "true" => {
+                let pk = match &current_peer {
+                    Some(pk) => pk,
+                    None => return reply(writer, libc::EINVAL),
+                };
+                config.replace_allowed_ips(pk);
+            }
+
+            "allowed_ip" => {
+                let pk = match &current_peer {
+                    Some(pk) => pk,
+                    None => return reply(writer, libc::EINVAL),
+                };
+                match parse_allowed_ip(value) {
+                    Some((ip, cidr)) => config.add_allowed_ip(pk, ip, cidr),
+                    None => return reply(writer, libc::EINVAL),
+                }
+            }
+
+            _ => {
+                log::debug!("UAPI: unrecognized key during set : {}", key);
+                return reply(writer, libc::EINVAL);
+            }
+        }
+    }
+
+    reply(writer, 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_allowed_ip_roundtrips_get_serialize_format() {
+        let (ip, cidr) = parse_allowed_ip("192.0.2.1/32").unwrap();
+        assert_eq!(ip, "192.0.2.1".parse::<IpAddr>().unwrap());
+        assert_eq!(cidr, 32);
+
+        let (ip, cidr) = parse_allowed_ip("2001:db8::/64").unwrap();
+        assert_eq!(ip, "2001:db8::".parse::<IpAddr>().unwrap());
+        assert_eq!(cidr, 64);
+    }
+
+    #[test]
+    fn parse_allowed_ip_rejects_malformed_entries() {
+        assert!(parse_allowed_ip("not-an-ip/32").is_none());
+        assert!(parse_allowed_ip("192.0.2.1").is_none());
+        assert!(parse_allowed_ip("192.0.2.1/not-a-cidr").is_none());
+    }
+
+    #[test]
+    fn decode_key_roundtrips_hex_encode() {
+        let key = [7u8; 32];
+        let value = hex::encode(key);
+        assert_eq!(decode_key(&value), Some(key));
+    }
+
+    #[test]
+    fn decode_key_rejects_wrong_length_and_bad_hex() {
+        assert!(decode_key(&hex::encode([0u8; 16])).is_none());
+        assert!(decode_key("not-hex").is_none());
+    }
+
+    #[test]
+    fn reply_err_maps_raw_os_error_instead_of_bubbling() {
+        let mut out = Vec::new();
+        let err = io::Error::from_raw_os_error(libc::EACCES);
+        reply_err(&mut out, err).unwrap();
+        assert_eq!(out, format!("errno={}\n", libc::EACCES).into_bytes());
+    }
+
+    #[test]
+    fn reply_err_maps_kind_when_no_os_code_is_attached() {
+        let mut out = Vec::new();
+        let err = io::Error::from(io::ErrorKind::PermissionDenied);
+        reply_err(&mut out, err).unwrap();
+        assert_eq!(out, format!("errno={}\n", libc::EACCES).into_bytes());
+    }
+
+    #[test]
+    fn reply_err_falls_back_to_eio_for_unmapped_kinds_without_an_os_code() {
+        let mut out = Vec::new();
+        let err = io::Error::new(io::ErrorKind::Other, "boom");
+        reply_err(&mut out, err).unwrap();
+        assert_eq!(out, format!("errno={}\n", libc::EIO).into_bytes());
+    }
+}
@@ -0,0 +1,178 @@
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use rand_core::{OsRng, RngCore};
+use spin::Mutex;
+use x25519_dalek::PublicKey;
+
+use super::macs;
+use super::peer::Peer;
+use super::types::HandshakeError;
+
+/* Owns the device-wide state the handshake layer needs but no single `Peer` can
+ * hold on its own: the pool of sender ids in use (so two outstanding handshakes
+ * never collide on the same id) and the cookie `Validator` used to decide, on
+ * every inbound handshake message, whether the sender must first present a
+ * valid `mac2` before any per-peer processing happens at all.
+ */
+pub struct Device<T> {
+    validator: macs::Validator,
+    under_load: AtomicBool,
+    ids: Mutex<HashSet<u32>>,
+    _peer: std::marker::PhantomData<T>,
+}
+
+impl<T> Device<T>
+where
+    T: Copy,
+{
+    pub fn new(pk: PublicKey) -> Self {
+        Self {
+            validator: macs::Validator::new(pk),
+            under_load: AtomicBool::new(false),
+            ids: Mutex::new(HashSet::new()),
+            _peer: std::marker::PhantomData,
+        }
+    }
+
+    /// Update whether this device currently considers itself under load. The
+    /// caller (the router, watching queue depth / handshake rate) is expected to
+    /// flip this on and off as conditions change; `filter_handshake` reads it on
+    /// every inbound message.
+    pub fn set_under_load(&self, under_load: bool) {
+        self.under_load.store(under_load, Ordering::Relaxed);
+    }
+
+    /// Reserve a fresh sender id, distinct from every id currently outstanding.
+    pub(crate) fn allocate(&self) -> u32 {
+        let mut ids = self.ids.lock();
+        loop {
+            let id = OsRng.next_u32();
+            if ids.insert(id) {
+                return id;
+            }
+        }
+    }
+
+    /// Release a sender id previously returned by `allocate`, once the handshake
+    /// state holding it is torn down.
+    pub(crate) fn release(&self, sender: u32) {
+        self.ids.lock().remove(&sender);
+    }
+
+    /// The entry point for every inbound handshake message, before any per-peer
+    /// state is touched: checks `mac2` against the load-based cookie and, if the
+    /// device is under load and the check fails (or `mac2` is absent), returns the
+    /// cookie-reply to send back instead of processing the message further.
+    ///
+    /// # Arguments
+    ///
+    /// * msg - the handshake message as received, up to (not including) mac2
+    /// * src - the source address bytes the message arrived from
+    /// * receiver - the sender id carried by `msg`, echoed back as the cookie
+    ///   reply's `receiver` field
+    /// * mac1 - mac1 as received, used as the cookie-reply's AEAD associated data
+    /// * mac2 - mac2 as received, if the message carried one
+    pub fn filter_handshake(
+        &self,
+        msg: &[u8],
+        src: &[u8],
+        receiver: u32,
+        mac1: &[u8; 16],
+        mac2: Option<&[u8; 16]>,
+    ) -> Option<macs::CookieReply> {
+        self.validator.enforce(
+            self.under_load.load(Ordering::Relaxed),
+            msg,
+            src,
+            receiver,
+            mac1,
+            mac2,
+        )
+    }
+
+    /// Decrypt and cache a cookie-reply message received for `peer`, so its next
+    /// outgoing messages (via `Peer::stamp_outgoing`) can carry a valid mac2.
+    pub fn handle_cookie_reply(
+        &self,
+        peer: &Peer<T>,
+        nonce: &[u8; 24],
+        cookie: &[u8; 32],
+    ) -> Result<(), HandshakeError> {
+        peer.cache_cookie_reply(nonce, cookie)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use x25519_dalek::StaticSecret;
+
+    fn responder_keypair() -> (StaticSecret, PublicKey) {
+        let sk = StaticSecret::from([9u8; 32]);
+        let pk = PublicKey::from(&sk);
+        (sk, pk)
+    }
+
+    #[test]
+    fn allocate_never_returns_an_id_already_outstanding() {
+        let (_, pk) = responder_keypair();
+        let device: Device<()> = Device::new(pk);
+        let first = device.allocate();
+        let second = device.allocate();
+        assert_ne!(first, second);
+        device.release(first);
+        device.release(second);
+    }
+
+    #[test]
+    fn filter_handshake_passes_through_when_not_under_load() {
+        let (_, pk) = responder_keypair();
+        let device: Device<()> = Device::new(pk);
+        let msg = b"initiation".to_vec();
+        assert!(device
+            .filter_handshake(&msg, b"10.0.0.1:1", 1, &[0u8; 16], None)
+            .is_none());
+    }
+
+    #[test]
+    fn filter_handshake_demands_a_cookie_when_under_load() {
+        let (_, pk) = responder_keypair();
+        let device: Device<()> = Device::new(pk);
+        device.set_under_load(true);
+        let msg = b"initiation".to_vec();
+        assert!(device
+            .filter_handshake(&msg, b"10.0.0.1:1", 1, &[0u8; 16], None)
+            .is_some());
+    }
+
+    #[test]
+    fn handle_cookie_reply_lets_the_peer_stamp_a_valid_mac2_next() {
+        let (_, responder_pk) = responder_keypair();
+        let device: Device<()> = Device::new(responder_pk);
+        device.set_under_load(true);
+
+        let peer_sk = StaticSecret::from([3u8; 32]);
+        let ss = peer_sk.diffie_hellman(&responder_pk);
+        let peer = Peer::new((), responder_pk, ss);
+
+        let msg = b"initiation".to_vec();
+        let (mac1, mac2) = peer.stamp_outgoing(&msg);
+        assert!(mac2.is_none()); // no cookie cached yet
+
+        let src = b"10.0.0.1:1".to_vec();
+        let reply = device
+            .filter_handshake(&msg, &src, 42, &mac1, mac2.as_ref())
+            .expect("under load with no mac2 must demand a cookie");
+
+        device
+            .handle_cookie_reply(&peer, &reply.nonce, &reply.cookie)
+            .unwrap();
+
+        let (_, mac2) = peer.stamp_outgoing(&msg);
+        let mac2 = mac2.expect("cookie is now cached");
+        assert!(device
+            .filter_handshake(&msg, &src, 42, &mac1, Some(&mac2))
+            .is_none());
+    }
+}
@@ -0,0 +1,320 @@
+use std::time::{Duration, Instant};
+
+use blake2s_simd::Params as Blake2s;
+use chacha20poly1305::aead::{generic_array::GenericArray, Aead, NewAead};
+use chacha20poly1305::XChaCha20Poly1305;
+use rand_core::{OsRng, RngCore};
+use spin::Mutex;
+use subtle::ConstantTimeEq;
+use x25519_dalek::PublicKey;
+
+use super::types::HandshakeError;
+
+const LABEL_MAC1: &[u8] = b"mac1----";
+const LABEL_COOKIE: &[u8] = b"cookie--";
+
+// a fresh cookie secret is handed out to the responder this often, per the WireGuard spec
+pub const COOKIE_SECRET_LIFETIME: Duration = Duration::from_secs(120);
+
+// a cookie received from a responder remains usable for the same window
+const COOKIE_LIFETIME: Duration = Duration::from_secs(120);
+
+fn keyed_mac16(key: &[u8], inputs: &[&[u8]]) -> [u8; 16] {
+    let mut params = Blake2s::new();
+    params.hash_length(16).key(key);
+    let mut state = params.to_state();
+    for input in inputs {
+        state.update(input);
+    }
+    let mut mac = [0u8; 16];
+    mac.copy_from_slice(state.finalize().as_bytes());
+    mac
+}
+
+// Hash(Label-Cookie || responder public key), used as the XAEAD key for cookie replies
+fn cookie_key(pk: &PublicKey) -> [u8; 32] {
+    let mut mac = [0u8; 32];
+    mac.copy_from_slice(
+        Blake2s::new()
+            .hash_length(32)
+            .to_state()
+            .update(LABEL_COOKIE)
+            .update(pk.as_bytes())
+            .finalize()
+            .as_bytes(),
+    );
+    mac
+}
+
+/* The sender side of the cookie mechanism.
+ *
+ * A `Generator` is held by every `Peer` and is responsible for computing `mac1` on
+ * outgoing handshake messages and, once the responder indicates it is under load
+ * (by returning a cookie reply), caching that cookie so that subsequent messages
+ * can carry a valid `mac2`.
+ */
+pub struct Generator {
+    pk: PublicKey, // public key of the remote peer (used to derive mac1)
+    last_mac1: Mutex<Option<[u8; 16]>>,
+    cookie: Mutex<Option<([u8; 16], Instant)>>,
+}
+
+impl Generator {
+    pub fn new(pk: PublicKey) -> Self {
+        Self {
+            pk,
+            last_mac1: Mutex::new(None),
+            cookie: Mutex::new(None),
+        }
+    }
+
+    /// Compute mac1 over `msg` and remember it, so that a cookie reply referencing
+    /// it can later be matched up and decrypted.
+    pub fn generate_mac1(&self, msg: &[u8]) -> [u8; 16] {
+        let key = Blake2s::new()
+            .hash_length(32)
+            .to_state()
+            .update(LABEL_MAC1)
+            .update(self.pk.as_bytes())
+            .finalize()
+            .as_bytes()
+            .to_owned();
+        let mac1 = keyed_mac16(&key, &[msg]);
+        *self.last_mac1.lock() = Some(mac1);
+        mac1
+    }
+
+    /// Compute mac2 over `msg` from the cached cookie, if any is still valid.
+    pub fn generate_mac2(&self, msg: &[u8]) -> Option<[u8; 16]> {
+        let cookie = self.cookie.lock();
+        match *cookie {
+            Some((cookie, received)) if received.elapsed() < COOKIE_LIFETIME => {
+                Some(keyed_mac16(&cookie, &[msg]))
+            }
+            _ => None,
+        }
+    }
+
+    /// Decrypt and cache a cookie reply received from the remote peer.
+    pub fn cache_cookie_reply(
+        &self,
+        nonce: &[u8; 24],
+        encrypted_cookie: &[u8; 32],
+    ) -> Result<(), HandshakeError> {
+        let last_mac1 = self.last_mac1.lock();
+        let aad = last_mac1.ok_or(HandshakeError::InvalidCookieReply)?;
+
+        let cipher = XChaCha20Poly1305::new(GenericArray::from_slice(&cookie_key(&self.pk)));
+        let cookie = cipher
+            .decrypt(
+                GenericArray::from_slice(nonce),
+                chacha20poly1305::aead::Payload {
+                    msg: encrypted_cookie,
+                    aad: &aad,
+                },
+            )
+            .map_err(|_| HandshakeError::InvalidCookieReply)?;
+
+        let mut mac = [0u8; 16];
+        mac.copy_from_slice(&cookie);
+        *self.cookie.lock() = Some((mac, Instant::now()));
+        Ok(())
+    }
+}
+
+/* The responder side of the cookie mechanism.
+ *
+ * A single `Validator` is held by the `Device` and is used, once the device decides
+ * it is under load, to check `mac2` on incoming messages and to mint cookie replies.
+ * The cookie handed out is `Mac(secret, source_address)`, where `secret` is replaced
+ * every `COOKIE_SECRET_LIFETIME` so that cookies cannot be replayed indefinitely.
+ */
+pub struct Validator {
+    pk: PublicKey, // responder's own public key (used to key the cookie-reply XAEAD)
+    secret: Mutex<(Instant, [u8; 32])>,
+}
+
+impl Validator {
+    pub fn new(pk: PublicKey) -> Self {
+        Self {
+            pk,
+            secret: Mutex::new((Instant::now(), Self::fresh_secret())),
+        }
+    }
+
+    fn fresh_secret() -> [u8; 32] {
+        let mut secret = [0u8; 32];
+        OsRng.fill_bytes(&mut secret);
+        secret
+    }
+
+    fn cookie(&self, src: &[u8]) -> [u8; 16] {
+        let mut secret = self.secret.lock();
+        if secret.0.elapsed() >= COOKIE_SECRET_LIFETIME {
+            *secret = (Instant::now(), Self::fresh_secret());
+        }
+        keyed_mac16(&secret.1, &[src])
+    }
+
+    /// Check `mac2` on a message received from `src`. Returns `true` if the supplied
+    /// `mac2` does not match the current cookie for `src`; whether a valid cookie is
+    /// required at all (the load decision) is left to the caller, see `enforce`.
+    ///
+    /// The comparison runs in constant time: `mac2` is attacker-controlled and a
+    /// byte-by-byte comparison would leak how many leading bytes it got right.
+    pub fn check_mac2(&self, msg: &[u8], src: &[u8], mac2: &[u8; 16]) -> bool {
+        let cookie = self.cookie(src);
+        let expect = keyed_mac16(&cookie, &[msg]);
+        !bool::from(expect.ct_eq(mac2))
+    }
+
+    /// The single entry point the device's inbound message path should call for
+    /// every handshake message once it has decided whether it is `under_load` for
+    /// the current window: folds `check_mac2` and `cookie_reply` into one call so
+    /// there is no path that checks a cookie without also being able to mint one,
+    /// or vice versa. Returns the cookie-reply to send back (instead of processing
+    /// the handshake message) when the device is under load and `mac2` is missing
+    /// or invalid; returns `None` when the message should proceed to handshake
+    /// processing as normal.
+    pub fn enforce(
+        &self,
+        under_load: bool,
+        msg: &[u8],
+        src: &[u8],
+        receiver: u32,
+        mac1: &[u8; 16],
+        mac2: Option<&[u8; 16]>,
+    ) -> Option<CookieReply> {
+        if !under_load {
+            return None;
+        }
+        match mac2 {
+            Some(mac2) if !self.check_mac2(msg, src, mac2) => None,
+            _ => Some(self.cookie_reply(receiver, mac1, src)),
+        }
+    }
+
+    /// Produce the body of an encrypted cookie-reply message for a message which
+    /// failed (or omitted) `mac2`, addressed to the given source.
+    pub fn cookie_reply(&self, receiver: u32, mac1: &[u8; 16], src: &[u8]) -> CookieReply {
+        let cookie = self.cookie(src);
+
+        let mut nonce = [0u8; 24];
+        OsRng.fill_bytes(&mut nonce);
+
+        let cipher = XChaCha20Poly1305::new(GenericArray::from_slice(&cookie_key(&self.pk)));
+        let encrypted_cookie = cipher
+            .encrypt(
+                GenericArray::from_slice(&nonce),
+                chacha20poly1305::aead::Payload {
+                    msg: &cookie,
+                    aad: mac1,
+                },
+            )
+            .expect("encryption over a fixed-size cookie cannot fail");
+
+        let mut buf = [0u8; 32];
+        buf.copy_from_slice(&encrypted_cookie);
+
+        CookieReply {
+            receiver,
+            nonce,
+            cookie: buf,
+        }
+    }
+}
+
+/// Wire representation of a cookie-reply message (the type which the transport
+/// layer frames with the leading message-type field and sends to `src`).
+pub struct CookieReply {
+    pub receiver: u32,
+    pub nonce: [u8; 24],
+    pub cookie: [u8; 32],
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use x25519_dalek::StaticSecret;
+
+    fn keypair() -> (StaticSecret, PublicKey) {
+        let sk = StaticSecret::from([1u8; 32]);
+        let pk = PublicKey::from(&sk);
+        (sk, pk)
+    }
+
+    // drive a message through a real mac1 -> cookie-reply -> cached-cookie -> mac2
+    // round trip between the sender's `Generator` and the responder's `Validator`
+    fn round_trip() -> (Generator, Validator, Vec<u8>, [u8; 16], u32) {
+        let (_, responder_pk) = keypair();
+        let generator = Generator::new(responder_pk);
+        let validator = Validator::new(responder_pk);
+
+        let msg = b"handshake initiation".to_vec();
+        let mac1 = generator.generate_mac1(&msg);
+        let src = b"198.51.100.1:51820".to_vec();
+        let receiver = 0xdead_beef;
+
+        let reply = validator.cookie_reply(receiver, &mac1, &src);
+        generator
+            .cache_cookie_reply(&reply.nonce, &reply.cookie)
+            .expect("cookie reply must decrypt against the mac1 it was addressed to");
+
+        (generator, validator, src, mac1, receiver)
+    }
+
+    #[test]
+    fn cookie_round_trip_produces_a_mac2_the_validator_accepts() {
+        let (generator, validator, src, _, _) = round_trip();
+        let msg = b"handshake initiation".to_vec();
+        let mac2 = generator.generate_mac2(&msg).expect("cookie was just cached");
+        assert!(!validator.check_mac2(&msg, &src, &mac2));
+    }
+
+    #[test]
+    fn check_mac2_rejects_a_mismatched_mac() {
+        let (generator, validator, src, _, _) = round_trip();
+        let msg = b"handshake initiation".to_vec();
+        let mut mac2 = generator.generate_mac2(&msg).unwrap();
+        mac2[0] ^= 0xff;
+        assert!(validator.check_mac2(&msg, &src, &mac2));
+    }
+
+    #[test]
+    fn enforce_passes_through_when_not_under_load() {
+        let (generator, validator, src, mac1, receiver) = round_trip();
+        let msg = b"handshake initiation".to_vec();
+        let mac2 = generator.generate_mac2(&msg).unwrap();
+        assert!(validator
+            .enforce(false, &msg, &src, receiver, &mac1, Some(&mac2))
+            .is_none());
+    }
+
+    #[test]
+    fn enforce_demands_a_cookie_reply_when_under_load_without_a_valid_mac2() {
+        let (generator, validator, src, mac1, receiver) = round_trip();
+        let msg = b"handshake initiation".to_vec();
+
+        // no mac2 at all
+        assert!(validator
+            .enforce(true, &msg, &src, receiver, &mac1, None)
+            .is_some());
+
+        // wrong mac2
+        let mut bad_mac2 = generator.generate_mac2(&msg).unwrap();
+        bad_mac2[0] ^= 0xff;
+        assert!(validator
+            .enforce(true, &msg, &src, receiver, &mac1, Some(&bad_mac2))
+            .is_some());
+    }
+
+    #[test]
+    fn enforce_admits_the_handshake_once_mac2_is_valid() {
+        let (generator, validator, src, mac1, receiver) = round_trip();
+        let msg = b"handshake initiation".to_vec();
+        let mac2 = generator.generate_mac2(&msg).unwrap();
+        assert!(validator
+            .enforce(true, &msg, &src, receiver, &mac1, Some(&mac2))
+            .is_none());
+    }
+}
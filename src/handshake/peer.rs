@@ -13,11 +13,17 @@ use super::device::Device;
 use super::macs;
 use super::timestamp;
 use super::types::*;
+use crate::platform::Endpoint;
 
 lazy_static! {
     pub static ref TIME_BETWEEN_INITIATIONS: Duration = Duration::from_millis(20);
 }
 
+// how many initiations sent by us may be outstanding (awaiting a response) at once;
+// bounds the work done in `find_initiation` and the number of sender ids a single
+// peer can hold reserved in the device's id table.
+const MAX_OUTSTANDING_INITIATIONS: usize = 2;
+
 /* Represents the recomputation and state of a peer.
  *
  * This type is only for internal use and not exposed.
@@ -27,9 +33,10 @@ pub struct Peer<T> {
     pub(crate) identifier: T,
 
     // mutable state
-    state: Mutex<State>,
+    state: Mutex<Vec<State>>, // outstanding handshake state(s); empty is equivalent to `Reset`
     timestamp: Mutex<Option<timestamp::TAI64N>>,
     last_initiation_consumption: Mutex<Option<Instant>>,
+    endpoint: Mutex<Option<Endpoint>>, // last source address an authenticated message roamed us to
 
     // state related to DoS mitigation fields
     pub(crate) macs: Mutex<macs::Generator>,
@@ -48,6 +55,13 @@ pub enum State {
         hs: GenericArray<u8, U32>,
         ck: GenericArray<u8, U32>,
     },
+    ResponseSent {
+        receiver: u32, // sender id of the initiation this responds to
+        sender: u32,   // assigned sender id of our response
+        eph_sk: StaticSecret,
+        hs: GenericArray<u8, U32>,
+        ck: GenericArray<u8, U32>,
+    },
 }
 
 impl Clone for State {
@@ -65,6 +79,19 @@ impl Clone for State {
                 hs: *hs,
                 ck: *ck,
             },
+            State::ResponseSent {
+                receiver,
+                sender,
+                eph_sk,
+                hs,
+                ck,
+            } => State::ResponseSent {
+                receiver: *receiver,
+                sender: *sender,
+                eph_sk: StaticSecret::from(eph_sk.to_bytes()),
+                hs: *hs,
+                ck: *ck,
+            },
         }
     }
 }
@@ -81,40 +108,134 @@ where
         Self {
             macs: Mutex::new(macs::Generator::new(pk)),
             identifier: identifier,
-            state: Mutex::new(State::Reset),
+            state: Mutex::new(Vec::new()),
             timestamp: Mutex::new(None),
             last_initiation_consumption: Mutex::new(None),
+            endpoint: Mutex::new(None),
             pk: pk,
             ss: ss,
             psk: [0u8; 32],
         }
     }
 
-    /// Return the state of the peer
+    /// Return the most recently entered state of the peer (`Reset` if there is
+    /// currently no outstanding handshake).
     ///
     /// # Arguments
     pub fn get_state(&self) -> State {
-        self.state.lock().clone()
+        match self.state.lock().last() {
+            Some(state) => state.clone(),
+            None => State::Reset,
+        }
+    }
+
+    /// Look up an outstanding initiation we sent by the sender id it was assigned.
+    /// A response carries that id back as its `receiver` field; consulting this
+    /// (rather than only the latest state) means a response to an earlier, since
+    /// superseded, initiation attempt is still accepted instead of being dropped
+    /// as stale when the peer has since sent a newer one.
+    ///
+    /// # Arguments
+    ///
+    /// * sender - the sender id originally assigned to the initiation
+    pub fn find_initiation(&self, sender: u32) -> Option<State> {
+        self.state
+            .lock()
+            .iter()
+            .find(|state| matches!(state, State::InitiationSent { sender: id, .. } if *id == sender))
+            .cloned()
+    }
+
+    /// Record `endpoint` as the address this peer is currently reachable at.
+    ///
+    /// Per the standard WireGuard roaming rule, only a source address a message
+    /// authenticated under this peer's keys arrived from may move the endpoint.
+    /// Within the handshake layer only `check_replay_flood` calls this, for inbound
+    /// initiations; authenticated responses and keepalives do not update the
+    /// endpoint here, and the public `Peer` does not forward this method.
+    pub fn set_endpoint(&self, endpoint: Endpoint) {
+        *self.endpoint.lock() = Some(endpoint);
+    }
+
+    /// Return the endpoint most recently recorded for this peer, if any.
+    pub fn get_endpoint(&self) -> Option<Endpoint> {
+        self.endpoint.lock().clone()
+    }
+
+    /// Decrypt and cache a cookie reply received from this peer, so that `mac2` can
+    /// be populated on subsequent handshake messages while the cookie remains valid.
+    ///
+    /// # Arguments
+    ///
+    /// * nonce - the 24-byte XChaCha20Poly1305 nonce from the cookie-reply message
+    /// * cookie - the encrypted cookie field from the cookie-reply message
+    pub fn cache_cookie_reply(
+        &self,
+        nonce: &[u8; 24],
+        cookie: &[u8; 32],
+    ) -> Result<(), HandshakeError> {
+        self.macs.lock().cache_cookie_reply(nonce, cookie)
     }
 
-    /// Set the state of the peer unconditionally
+    /// Stamp mac1 (and mac2, if a cookie is currently cached for this peer) on an
+    /// outgoing handshake message, so the device can attach both before sending.
+    pub fn stamp_outgoing(&self, msg: &[u8]) -> ([u8; 16], Option<[u8; 16]>) {
+        let macs = self.macs.lock();
+        let mac1 = macs.generate_mac1(msg);
+        let mac2 = macs.generate_mac2(msg);
+        (mac1, mac2)
+    }
+
+    /// Set the state of the peer unconditionally.
+    ///
+    /// `Reset` clears every outstanding state. `InitiationSent` is appended to the
+    /// outstanding set, evicting (and releasing the sender id of) the oldest
+    /// `InitiationSent` (never a `ResponseSent`) once more than `MAX_OUTSTANDING_INITIATIONS` are in flight. `ResponseSent`
+    /// supersedes any initiations of our own: once we have committed to responding
+    /// to the peer, our own outstanding initiations can no longer complete usefully.
     ///
     /// # Arguments
     ///
-    pub fn set_state(&self, state_new: State) {
-        *self.state.lock() = state_new;
+    /// * device - the device the peer belongs to, used to release superseded sender ids
+    /// * state_new - the new state to enter
+    pub fn set_state(&self, device: &Device<T>, state_new: State) {
+        let mut state = self.state.lock();
+        match state_new {
+            State::Reset => release_initiations(device, state.drain(..)),
+            State::InitiationSent { .. } => {
+                state.push(state_new);
+                // only our own outstanding initiations count toward the bound: a
+                // `ResponseSent` occupies the same vec but is a distinct, unbounded
+                // slot and must never be evicted here, or a lost/reordered response
+                // would tear down the in-flight responder handshake out from under us
+                while count_initiations(&state) > MAX_OUTSTANDING_INITIATIONS {
+                    let idx = state
+                        .iter()
+                        .position(|s| matches!(s, State::InitiationSent { .. }))
+                        .expect("count_initiations > 0 implies an InitiationSent exists");
+                    release_initiations(device, std::iter::once(state.remove(idx)));
+                }
+            }
+            State::ResponseSent { .. } => {
+                release_initiations(device, state.drain(..));
+                state.push(state_new);
+            }
+        }
     }
 
     /// Set the mutable state of the peer conditioned on the timestamp being newer
     ///
     /// # Arguments
     ///
-    /// * st_new - The updated state of the peer
-    /// * ts_new - The associated timestamp
+    /// * device - the device the peer belongs to, used to release superseded sender ids
+    /// * timestamp_new - the timestamp decrypted from the inbound initiation
+    /// * endpoint - the source address the (now-authenticated) initiation arrived from;
+    ///   recorded via `set_endpoint` once the replay/flood checks below pass
     pub fn check_replay_flood(
         &self,
         device: &Device<T>,
         timestamp_new: &timestamp::TAI64N,
+        endpoint: Endpoint,
     ) -> Result<(), HandshakeError> {
         let mut state = self.state.lock();
         let mut timestamp = self.timestamp.lock();
@@ -140,16 +261,38 @@ where
             _ => (),
         }
 
-        // reset state
-        match *state {
-            State::InitiationSent { sender, .. } => device.release(sender),
-            _ => (),
-        }
+        // an inbound initiation means any handshake we had in flight as an initiator
+        // can no longer complete meaningfully; release it and accept the new one
+        release_initiations(device, state.drain(..));
 
         // update replay & flood protection
-        *state = State::Reset;
         *timestamp = Some(*timestamp_new);
         *last_initiation_consumption = Some(Instant::now());
+
+        // the timestamp above only decrypts correctly under this peer's static key,
+        // so reaching this point is itself the authentication the roaming rule requires
+        self.set_endpoint(endpoint);
+
         Ok(())
     }
 }
+
+// number of our own outstanding initiations, excluding any `ResponseSent`
+fn count_initiations(state: &[State]) -> usize {
+    state
+        .iter()
+        .filter(|s| matches!(s, State::InitiationSent { .. }))
+        .count()
+}
+
+// release the sender id of every outstanding `InitiationSent`/`ResponseSent` in `states`
+// back to the device
+fn release_initiations<T>(device: &Device<T>, states: impl Iterator<Item = State>) {
+    for state in states {
+        match state {
+            State::InitiationSent { sender, .. } => device.release(sender),
+            State::ResponseSent { sender, .. } => device.release(sender),
+            State::Reset => {}
+        }
+    }
+}
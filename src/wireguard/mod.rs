@@ -14,6 +14,16 @@ mod tests;
 ///
 /// - TUN type, specifying how packets are received on the interface side: a reader/writer and MTU reporting interface.
 /// - Bind type, specifying how WireGuard messages are sent/received from the internet and what constitutes an "endpoint"
+///
+/// Peers roam: once a message from a peer passes authentication under its keys,
+/// the source address it arrived from is recorded as the peer's new endpoint
+/// (only cryptographically authenticated traffic may move an endpoint). The
+/// internal `handshake::Peer` already does this for inbound initiations and
+/// exposes `set_endpoint`/`get_endpoint` as the hook for the rest of this
+/// roaming (authenticated responses and keepalives, and seeding a value from a
+/// UAPI `endpoint=` line) to go through. The `Peer` re-exported below does not
+/// yet forward those two methods — until it does, a caller cannot reach this
+/// hook through the public API, only through `handshake::Peer` directly.
 pub use wireguard::{Peer, Wireguard};
 
 #[cfg(test)]